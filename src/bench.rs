@@ -1,9 +1,12 @@
-use std::collections::VecDeque;
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
 use std::io::{Error, ErrorKind, Read, Result, Write};
 use std::net::SocketAddr;
-use std::str::FromStr;
+use std::result::Result as StdResult;
+use std::str::{self, FromStr};
 use std::iter;
 use std::sync::mpsc;
+use std::sync::Arc;
 use std::mem;
 use std::thread;
 use std::process::exit;
@@ -12,17 +15,58 @@ use time;
 use histogram::{Histogram, HistogramConfig};
 use mio::{PollOpt, EventLoop, EventSet, Handler, Token};
 use mio::tcp::TcpStream;
+use mio::udp::UdpSocket;
 use mio::util::Slab;
 use rand::{Rng, XorShiftRng};
+use rustls::{self, ClientConfig, ClientSession, Session};
+use webpki;
+
+use protocol;
 
 /// Initial read and write buffer size.
 const BUF_SIZE: usize = 4096;
+/// Initial delay before the first reconnect attempt after a dropped connection.
+const RECONNECT_BACKOFF_MIN_MS: u64 = 50;
+/// Reconnect backoff is capped here so a persistently unreachable server is retried at a steady
+/// rate rather than backing off indefinitely.
+const RECONNECT_BACKOFF_MAX_MS: u64 = 5_000;
+/// How long a UDP request may go unacknowledged before it is given up as dropped. Unlike TCP, a
+/// UDP reply can simply vanish; without a cutoff a lost reply's send time would sit in the
+/// outstanding bookkeeping forever.
+const UDP_REPLY_TIMEOUT_MS: u64 = 2_000;
+/// Largest possible UDP datagram payload.
+const MAX_UDP_DATAGRAM: usize = 65_507;
+/// Width of the hex-encoded key index embedded at the front of a populated UDP value, used to
+/// match GET replies back to their request; see `Bench::udp_writable`.
+const UDP_KEY_INDEX_WIDTH: usize = 16;
+
+/// Which traffic pattern the benchmark drives against the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BenchMode {
+    /// Write-only traffic: continuously PUT new keys.
+    Put,
+    /// Read-only traffic: PUT `count` keys, then GET random keys from that set.
+    Get,
+}
+
+/// The phase a `bench-get` run is in. `bench-put` runs are always `Bench`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    /// Populating the keyspace with PUTs ahead of the read benchmark.
+    Populate,
+    /// Issuing the benchmarked request traffic and recording latencies.
+    Bench,
+}
 
 pub struct Bench {
     connections: Slab<Connection>,
+    addr: SocketAddr,
+    mode: BenchMode,
+    phase: Phase,
     pid: i32,
     concurrency: u32,
     entries_written: usize,
+    entries_acked: usize,
     val_size: usize,
     batch_size: usize,
     count: usize,
@@ -30,34 +74,136 @@ pub struct Bench {
     hist: Histogram,
     hist_send: mpsc::Sender<Histogram>,
     rand: XorShiftRng,
+    binary: bool,
+    /// Running count of reconnect attempts made across all connections, for diagnostics.
+    reconnects: u64,
+    /// Current retry backoff, in ms, for connections that are mid-reconnect.
+    reconnect_backoff: HashMap<Token, u64>,
+    /// Shared rate limiter over all connections' enqueued requests, or `None` for unlimited.
+    bucket: Option<TokenBucket>,
+    /// Whether to drive the benchmark over UDP datagrams rather than TCP streams.
+    udp: bool,
+    /// UDP connection state, used instead of `connections` when `udp` is set.
+    udp_connections: Slab<UdpConnection>,
+    /// Requests given up as dropped (no reply within `UDP_REPLY_TIMEOUT_MS`), excluded from the
+    /// histogram.
+    udp_dropped: u64,
+    /// Whether to wrap every `connections` socket in a rustls client session, so encrypted
+    /// throughput/latency can be measured against the plaintext baseline.
+    tls: bool,
+    /// Client TLS configuration, shared across every connection's `ClientSession` (including
+    /// ones created by `retry_connect`); `None` unless `tls` is set.
+    tls_config: Option<Arc<ClientConfig>>,
+}
+
+/// Identifies what a scheduled `event_loop` timeout is for.
+#[derive(Debug, Clone, Copy)]
+enum TimeoutKind {
+    /// Time to flush the histogram to the reporter thread.
+    Report,
+    /// Time to retry connecting the given (disconnected) connection.
+    Reconnect(Token),
+    /// Time to resume enqueueing requests on the given connection after being paced out by the
+    /// token bucket.
+    ResumeWrite(Token),
+}
+
+/// A token-bucket rate limiter shared across all of a benchmark's connections, used to cap
+/// offered load to `--target-qps` so latency can be measured at a fixed offered rate rather than
+/// only at saturation.
+struct TokenBucket {
+    /// Maximum number of banked tokens (one second of burst, at the target rate).
+    capacity: f64,
+    tokens: f64,
+    /// Tokens added per nanosecond.
+    rate: f64,
+    last_refill: u64,
+}
+
+impl TokenBucket {
+    fn new(target_qps: u64, now: u64) -> TokenBucket {
+        let capacity = target_qps as f64;
+        TokenBucket { capacity: capacity, tokens: capacity, rate: target_qps as f64 / 1e9, last_refill: now }
+    }
+
+    fn refill(&mut self, now: u64) {
+        let elapsed_ns = now.saturating_sub(self.last_refill) as f64;
+        self.tokens = (self.tokens + elapsed_ns * self.rate).min(self.capacity);
+        self.last_refill = now;
+    }
+
+    /// Attempts to consume a single token, returning whether one was available.
+    fn try_take(&mut self, now: u64) -> bool {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Milliseconds until the next token will be available, at least 1.
+    fn wait_ms(&self) -> u64 {
+        let needed = (1.0 - self.tokens).max(0.0);
+        let wait_ns = (needed / self.rate).ceil() as u64;
+        cmp::max(1, (wait_ns + 999_999) / 1_000_000)
+    }
 }
 
 impl Bench {
-    pub fn start(port: u32,
+    pub fn start(mode: BenchMode,
+                 port: u32,
                  pid: i32,
                  concurrency: u32,
                  val_size: usize,
                  batch_size: usize,
                  count: usize,
-                 report_duration: u64) -> Result<()> {
-        info!("Starting benchmark of simple-kv server with listening port {} and pid {}", port, pid);
-        info!("concurrency: {}, val-size: {}b, batch-size: {}, count: {}, report-duration: {:?}",
-              concurrency, val_size, batch_size, count, report_duration);
+                 report_duration: u64,
+                 binary: bool,
+                 target_qps: u64,
+                 udp: bool,
+                 tls: bool) -> Result<()> {
+        if mode == BenchMode::Get && count == 0 {
+            return Err(Error::new(ErrorKind::InvalidInput,
+                                   "bench-get requires --count > 0: it populates exactly that \
+                                    many keys before benchmarking GETs against them"));
+        }
+
+        info!("Starting {:?} benchmark of simple-kv server with listening port {} and pid {} ({})",
+              mode, port, pid,
+              if udp { "udp" } else if tls { "tls" } else if binary { "binary" } else { "text" });
+        info!("concurrency: {}, val-size: {}b, batch-size: {}, count: {}, report-duration: {:?}, target-qps: {}",
+              concurrency, val_size, batch_size, count, report_duration, target_qps);
         let addr = SocketAddr::from_str(&format!("127.0.0.1:{}", port)).unwrap();
 
         let mut event_loop = try!(EventLoop::<Bench>::new());
-        let mut connections = Slab::new(concurrency as usize);
-
-        for _ in 0..concurrency {
-            let mut connection = Connection::new(try!(TcpStream::connect(&addr)));
-            let token = connections.insert(connection).unwrap();
-            try!(event_loop.register_opt(&connections[token].socket,
-                                         token,
-                                         EventSet::all(),
-                                         PollOpt::edge() | PollOpt::oneshot()));
+        let mut connections = Slab::new(if udp { 0 } else { concurrency as usize });
+        let mut udp_connections = Slab::new(if udp { concurrency as usize } else { 0 });
+        let tls_config = if tls { Some(tls_client_config()) } else { None };
+
+        if udp {
+            for _ in 0..concurrency {
+                let local_addr = SocketAddr::from_str("127.0.0.1:0").unwrap();
+                let socket = try!(UdpSocket::bound(&local_addr));
+                let token = udp_connections.insert(UdpConnection::new(socket)).unwrap();
+                try!(event_loop.register_opt(&udp_connections[token].socket,
+                                             token,
+                                             EventSet::readable() | EventSet::writable(),
+                                             PollOpt::edge() | PollOpt::oneshot()));
+            }
+        } else {
+            for _ in 0..concurrency {
+                let connection = Connection::new(try!(TcpStream::connect(&addr)), &tls_config);
+                let token = connections.insert(connection).unwrap();
+                try!(event_loop.register_opt(&connections[token].socket,
+                                             token,
+                                             EventSet::all(),
+                                             PollOpt::edge() | PollOpt::oneshot()));
+            }
         }
 
-        event_loop.timeout_ms((), report_duration).unwrap();
+        event_loop.timeout_ms(TimeoutKind::Report, report_duration).unwrap();
 
         let (hist_send, hist_recv) = mpsc::channel();
 
@@ -65,11 +211,20 @@ impl Bench {
             reporter(hist_recv);
         });
 
+        let phase = match mode {
+            BenchMode::Put => Phase::Bench,
+            BenchMode::Get => Phase::Populate,
+        };
+
         let mut bench = Bench {
             connections: connections,
+            addr: addr,
+            mode: mode,
+            phase: phase,
             pid: pid,
             concurrency: concurrency,
             entries_written: 0,
+            entries_acked: 0,
             count: count,
             val_size: val_size,
             batch_size: batch_size,
@@ -77,103 +232,442 @@ impl Bench {
             hist: create_hist(),
             hist_send: hist_send,
             rand: XorShiftRng::new_unseeded(),
+            binary: binary,
+            reconnects: 0,
+            reconnect_backoff: HashMap::new(),
+            bucket: if target_qps > 0 {
+                Some(TokenBucket::new(target_qps, time::precise_time_ns()))
+            } else {
+                None
+            },
+            udp: udp,
+            udp_connections: udp_connections,
+            udp_dropped: 0,
+            tls: tls,
+            tls_config: tls_config,
         };
 
         event_loop.run(&mut bench)
     }
 
-    /// Called when the connection's socket is writable.
-    fn writable(&mut self, token: Token) -> Result<()> {
-        let &mut Bench { ref mut connections, ref mut rand,
-                         batch_size, ref mut entries_written, val_size, .. } = self;
-        let connection = &mut connections[token];
+    /// Removes the dead connection for `token` and kicks off a reconnect attempt in its place,
+    /// so a single dropped or reset connection doesn't abort the whole benchmark run.
+    fn reconnect(&mut self, event_loop: &mut EventLoop<Bench>, token: Token, reason: &str) {
+        warn!("connection {:?} {}; reconnecting", token, reason);
+        self.connections.remove(token);
+        self.retry_connect(event_loop, token);
+    }
 
-        let message_size = 22 + val_size; // 'PUT' + 16 byte key + 2 spaces + newline
+    /// Attempts to (re)connect `token` to the benchmark's server address, scheduling a
+    /// backed-off retry via the event loop on failure.
+    fn retry_connect(&mut self, event_loop: &mut EventLoop<Bench>, token: Token) {
+        self.reconnects += 1;
+        match TcpStream::connect(&self.addr) {
+            Ok(socket) => {
+                self.reconnect_backoff.remove(&token);
+                let connection = Connection::new(socket, &self.tls_config);
+                let inserted = self.connections.insert(connection)
+                                   .unwrap_or_else(|_| panic!("slab full while reconnecting {:?}", token));
+                if inserted != token {
+                    warn!("reconnect for {:?} landed in slab slot {:?} instead; registering that one",
+                          token, inserted);
+                }
+                if let Err(error) = event_loop.register_opt(&self.connections[inserted].socket, inserted,
+                                                            EventSet::all(),
+                                                            PollOpt::edge() | PollOpt::oneshot()) {
+                    warn!("failed to register reconnected socket {:?}: {}", inserted, error);
+                } else {
+                    info!("reconnected {:?} to {} (reconnect #{})", inserted, self.addr, self.reconnects);
+                }
+            },
+            Err(error) => {
+                let backoff = self.reconnect_backoff.get(&token).cloned()
+                                  .unwrap_or(RECONNECT_BACKOFF_MIN_MS);
+                warn!("reconnect #{} to {} failed: {}; retrying {:?} in {}ms",
+                      self.reconnects, self.addr, error, token, backoff);
+                self.reconnect_backoff.insert(token, cmp::min(backoff * 2, RECONNECT_BACKOFF_MAX_MS));
+                event_loop.timeout_ms(TimeoutKind::Reconnect(token), backoff).unwrap();
+            },
+        }
+    }
 
-        while connection.write_buf.len() < message_size * batch_size {
-            connection.write_buf.extend(b"PUT ".iter());
-            write!(&mut connection.write_buf, "{:016X}", *entries_written);
-            connection.write_buf.push(' ' as u8);
-            connection.write_buf.extend(rand.gen_ascii_chars().map(|c| c as u8).take(val_size as usize));
-            connection.write_buf.push('\n' as u8);
-            *entries_written += 1;
+    /// Called when the connection's socket is writable.
+    fn writable(&mut self, event_loop: &mut EventLoop<Bench>, token: Token) -> Result<()> {
+        match self.phase {
+            Phase::Populate => self.writable_put(event_loop, token, false),
+            Phase::Bench => match self.mode {
+                BenchMode::Put => self.writable_put(event_loop, token, true),
+                BenchMode::Get => self.writable_get(event_loop, token),
+            },
         }
+    }
+
+    /// Schedules a timeout to retry enqueueing on `token` once the token bucket has a token
+    /// available again.
+    fn schedule_resume(&mut self, event_loop: &mut EventLoop<Bench>, token: Token) {
+        let wait_ms = self.bucket.as_ref().map(TokenBucket::wait_ms).unwrap_or(1);
+        event_loop.timeout_ms(TimeoutKind::ResumeWrite(token), wait_ms).unwrap();
+    }
 
+    /// Fills the connection's write buffer with `PUT` messages for fresh, sequentially-keyed
+    /// entries. When `record` is true (the `bench-put` benchmark), each write's send time is
+    /// recorded so its ack can be timed; during the `bench-get` populate phase sends are
+    /// unrecorded, since only GET latencies are benchmarked.
+    fn writable_put(&mut self, event_loop: &mut EventLoop<Bench>, token: Token, record: bool) -> Result<()> {
+        let mut paced_out = false;
         let mut idx = 0;
-        while idx < connection.write_buf.len() {
-            match connection.socket.write(&connection.write_buf[idx..]) {
-                Ok(0) => return Err(Error::new(ErrorKind::WriteZero,
-                                               "unable to write to socket")),
-                Ok(n) => idx += n,
-                Err(ref error) if error.kind() == ErrorKind::WouldBlock => break,
-                Err(error) => return Err(error),
+        let message_size;
+        {
+            let &mut Bench { ref mut connections, ref mut rand, batch_size, binary,
+                             ref mut entries_written, val_size, count, ref mut bucket, .. } = self;
+            let connection = &mut connections[token];
+
+            // 'PUT' + 16 byte key + 2 spaces + newline, or the binary framing equivalent.
+            message_size = if binary { 27 + val_size } else { 22 + val_size };
+
+            while connection.write_buf.len() < message_size * batch_size
+               && (count == 0 || *entries_written < count) {
+                if let Some(bucket) = bucket.as_mut() {
+                    if !bucket.try_take(time::precise_time_ns()) {
+                        paced_out = true;
+                        break;
+                    }
+                }
+                let key = format!("{:016X}", *entries_written);
+                if binary {
+                    let value: Vec<u8> = rand.gen_ascii_chars().map(|c| c as u8).take(val_size).collect();
+                    protocol::encode_put(&mut connection.write_buf, key.as_bytes(), &value);
+                } else {
+                    connection.write_buf.extend(b"PUT ".iter());
+                    connection.write_buf.extend(key.as_bytes());
+                    connection.write_buf.push(' ' as u8);
+                    connection.write_buf.extend(rand.gen_ascii_chars().map(|c| c as u8).take(val_size as usize));
+                    connection.write_buf.push('\n' as u8);
+                }
+                *entries_written += 1;
+            }
+
+            idx = try!(connection.flush_write());
+
+            if record {
+                let send_time: u64 = time::precise_time_ns();
+                let messages_sent = (connection.bytes_sent + idx) / message_size
+                                  - connection.bytes_sent / message_size;
+                debug!("sent {} messages from {:?}", messages_sent, token);
+                connection.send_times.extend(iter::repeat(send_time).take(messages_sent));
             }
+
+            connection.write_buf.drain(..idx).count();
         }
-        let send_time: u64 = time::precise_time_ns();
 
-        let messages_sent = (connection.bytes_sent + idx) / message_size
-                          - connection.bytes_sent / message_size;
+        if paced_out {
+            self.schedule_resume(event_loop, token);
+        }
+        Ok(())
+    }
 
-        debug!("sent {} messages from {:?}", messages_sent, token);
+    /// Fills the connection's write buffer with `GET` messages against random keys from the
+    /// already-populated keyspace, recording a send time per request.
+    fn writable_get(&mut self, event_loop: &mut EventLoop<Bench>, token: Token) -> Result<()> {
+        let mut paced_out = false;
+        {
+            let &mut Bench { ref mut connections, ref mut rand, batch_size, entries_written, binary,
+                             ref mut bucket, .. } = self;
+            let connection = &mut connections[token];
+
+            while connection.write_buf.len() < BUF_SIZE / 2 && connection.send_times.len() < batch_size as usize {
+                if let Some(bucket) = bucket.as_mut() {
+                    if !bucket.try_take(time::precise_time_ns()) {
+                        paced_out = true;
+                        break;
+                    }
+                }
+                let key = format!("{:016X}", rand.gen_range(0, entries_written));
+                if binary {
+                    protocol::encode_get(&mut connection.write_buf, key.as_bytes());
+                } else {
+                    connection.write_buf.extend(b"GET ".iter());
+                    connection.write_buf.extend(key.as_bytes());
+                    connection.write_buf.push('\n' as u8);
+                }
+            }
+
+            // With TLS, `flush_write` hands the whole buffer to the session in one shot (see its
+            // doc comment), so the count has to be taken across the whole pre-flush buffer rather
+            // than just the bytes the raw socket write accepted.
+            let whole_buf_sent = if binary {
+                protocol::count_frames(&connection.write_buf)
+            } else {
+                connection.write_buf.iter().filter(|&&b| b == '\n' as u8).count()
+            };
+            let idx = try!(connection.flush_write());
+            let sent = if connection.tls.is_some() {
+                whole_buf_sent
+            } else if binary {
+                // A partial write can split a frame at any byte boundary, so scan for complete
+                // frames within the bytes actually written rather than assuming it landed on a
+                // frame boundary (which would desync `send_times` from what was really sent).
+                protocol::count_frames(&connection.write_buf[..idx])
+            } else {
+                connection.write_buf[..idx].iter().filter(|&&b| b == '\n' as u8).count()
+            };
+
+            let send_time: u64 = time::precise_time_ns();
+            debug!("sent {} messages from {:?}", sent, token);
+            connection.send_times.extend(iter::repeat(send_time).take(sent));
+            connection.write_buf.drain(..idx).count();
+        }
 
-        connection.send_times.extend(iter::repeat(send_time).take(messages_sent));
-        connection.write_buf.drain(..idx).count();
+        if paced_out {
+            self.schedule_resume(event_loop, token);
+        }
         Ok(())
     }
 
-    /// Receive responses, and return a vector of response latencies.
+    /// Receive responses, and update the histogram and populate-ack counters as appropriate.
     fn readable(&mut self, token: Token) -> Result<()> {
         let recv_time: u64 = time::precise_time_ns();
+        let binary = self.binary;
+        // Whether this connection is currently expecting PUT acks (fixed-size) rather than GET
+        // values (length-prefixed / variable-size).
+        let expect_put_ack = self.phase == Phase::Populate || self.mode == BenchMode::Put;
 
-        let &mut Bench { ref mut connections, ref mut hist, .. } = self;
+        let &mut Bench { ref mut connections, .. } = self;
         let connection = &mut connections[token];
 
-        match connection.socket.read_to_end(&mut connection.read_buf) {
-            Ok(_) => (),
-            Err(ref error) if error.kind() == ErrorKind::WouldBlock => (),
-            Err(error) => return Err(error),
-        }
+        try!(connection.fill_read());
 
-        assert!(connection.read_buf.chunks(3).all(|response| response == b"OK\n"));
-        let responses = connection.read_buf.len() / 3;
-        connection.read_buf.drain(..responses * 3);
+        let responses = if binary {
+            if expect_put_ack {
+                protocol::count_status_responses(&mut connection.read_buf)
+            } else {
+                protocol::count_value_responses(&mut connection.read_buf)
+            }
+        } else {
+            // Responses are newline-delimited; `PUT`/populate acks are the fixed 3-byte "OK\n",
+            // while `GET` responses are variable-length (the value, or "NONE\n"), so count
+            // completed responses by scanning for '\n' rather than assuming a fixed reply size.
+            let n = connection.read_buf.iter().filter(|&&b| b == '\n' as u8).count();
+            connection.read_buf.clear();
+            n
+        };
 
         debug!("received {} responses to {:?}", responses, token);
 
-        for send_time in connection.send_times.iter().rev().take(responses) {
-            hist.increment(recv_time - send_time);
+        match self.phase {
+            Phase::Populate => {
+                self.entries_acked += responses;
+                if self.entries_acked >= self.count {
+                    info!("populate phase complete: {} entries written and acked", self.entries_acked);
+                    self.phase = Phase::Bench;
+                }
+            },
+            Phase::Bench => {
+                let hist = &mut self.hist;
+                for send_time in connection.send_times.iter().rev().take(responses) {
+                    hist.increment(recv_time - send_time);
+                }
+                let len = connection.send_times.len() - responses;
+                connection.send_times.truncate(len);
+            },
+        }
+        Ok(())
+    }
+
+    /// Handles a readiness event for a UDP socket: receives any pending replies, then attempts
+    /// to send more requests if writable, then reregisters for the next event.
+    fn udp_ready(&mut self, event_loop: &mut EventLoop<Bench>, token: Token, events: EventSet) {
+        if events.is_readable() {
+            self.udp_readable(token);
+        }
+        if events.is_writable() {
+            self.udp_writable(event_loop, token).unwrap();
+        }
+
+        let socket = &self.udp_connections[token].socket;
+        event_loop.reregister(socket, token, EventSet::readable() | EventSet::writable(),
+                              PollOpt::edge() | PollOpt::oneshot()).unwrap();
+    }
+
+    /// Drains pending reply datagrams for `token`, matching each to its request by send order
+    /// (PUT acks) or by the key index embedded in the value (GET replies), and recording its
+    /// latency. A reply that matches nothing outstanding is counted as a stray/drop.
+    fn udp_readable(&mut self, token: Token) {
+        let recv_time: u64 = time::precise_time_ns();
+        let phase = self.phase;
+        let expect_put_ack = phase == Phase::Populate || self.mode == BenchMode::Put;
+
+        let &mut Bench { ref mut udp_connections, ref mut hist, ref mut entries_acked,
+                          ref mut udp_dropped, count, .. } = self;
+        let connection = &mut udp_connections[token];
+        let mut buf = [0u8; MAX_UDP_DATAGRAM];
+
+        loop {
+            let len = match connection.socket.recv_from(&mut buf) {
+                Ok(Some((len, _src))) => len,
+                Ok(None) => break,
+                Err(ref error) if error.kind() == ErrorKind::WouldBlock => break,
+                Err(error) => { warn!("error reading udp reply on {:?}: {}", token, error); break; },
+            };
+
+            let send_time = if expect_put_ack {
+                connection.send_times.pop_front()
+            } else {
+                parse_udp_key_index(&buf[..len])
+                    .and_then(|key_idx| connection.outstanding.get_mut(&key_idx))
+                    .and_then(|times| times.pop_front())
+            };
+
+            match send_time {
+                Some(send_time) if phase == Phase::Bench => hist.increment(recv_time - send_time),
+                Some(_) => *entries_acked += 1,
+                None => *udp_dropped += 1,
+            }
+        }
+
+        if phase == Phase::Populate && *entries_acked >= count {
+            info!("populate phase complete: {} entries written and acked", entries_acked);
+            self.phase = Phase::Bench;
         }
+    }
 
-        let len = connection.send_times.len() - responses;
-        connection.send_times.truncate(len);
+    /// Sends up to `batch_size` request datagrams for `token`. Unlike the TCP write path there is
+    /// no partial-write buffering to manage: each request is one complete datagram.
+    fn udp_writable(&mut self, event_loop: &mut EventLoop<Bench>, token: Token) -> Result<()> {
+        let mut paced_out = false;
+        {
+            let &mut Bench { ref mut udp_connections, ref mut rand, batch_size, val_size, addr,
+                              phase, mode, ref mut entries_written, count, ref mut bucket, .. } = self;
+            let connection = &mut udp_connections[token];
+            let send_put = phase == Phase::Populate || mode == BenchMode::Put;
+            let mut sent = 0;
+
+            while sent < batch_size as usize {
+                if send_put && count > 0 && *entries_written >= count {
+                    break;
+                }
+                if let Some(bucket) = bucket.as_mut() {
+                    if !bucket.try_take(time::precise_time_ns()) {
+                        paced_out = true;
+                        break;
+                    }
+                }
+
+                let key_idx = if send_put { *entries_written } else { rand.gen_range(0, *entries_written) };
+                let key = format!("{:016X}", key_idx);
+                let mut datagram = Vec::with_capacity(BUF_SIZE);
+
+                if send_put {
+                    datagram.extend(b"PUT ".iter());
+                    datagram.extend(key.as_bytes());
+                    datagram.push(' ' as u8);
+                    if phase == Phase::Populate {
+                        // Embed the key index at the front of the value so a later GET reply can
+                        // be matched back to its request even though UDP may reorder or drop it.
+                        datagram.extend(key.as_bytes());
+                        datagram.extend(iter::repeat('x' as u8).take(val_size.saturating_sub(key.len())));
+                    } else {
+                        datagram.extend(rand.gen_ascii_chars().map(|c| c as u8).take(val_size));
+                    }
+                    datagram.push('\n' as u8);
+                } else {
+                    datagram.extend(b"GET ".iter());
+                    datagram.extend(key.as_bytes());
+                    datagram.push('\n' as u8);
+                }
+
+                match connection.socket.send_to(&datagram, &addr) {
+                    Ok(Some(_)) => {
+                        let send_time = time::precise_time_ns();
+                        if send_put {
+                            connection.send_times.push_back(send_time);
+                            *entries_written += 1;
+                        } else {
+                            connection.outstanding.entry(key_idx).or_insert_with(VecDeque::new)
+                                      .push_back(send_time);
+                        }
+                        sent += 1;
+                    },
+                    Ok(None) => break,
+                    Err(ref error) if error.kind() == ErrorKind::WouldBlock => break,
+                    Err(error) => return Err(error),
+                }
+            }
+        }
+
+        if paced_out {
+            self.schedule_resume(event_loop, token);
+        }
         Ok(())
     }
+
+    /// Scans all UDP connections for requests that have gone unanswered for longer than
+    /// `UDP_REPLY_TIMEOUT_MS` and counts them as dropped, so a lost reply doesn't sit in the
+    /// outstanding-request bookkeeping forever.
+    fn udp_reap_drops(&mut self) {
+        let now = time::precise_time_ns();
+        let threshold_ns = UDP_REPLY_TIMEOUT_MS * 1_000_000;
+        let mut dropped = 0;
+
+        for connection in self.udp_connections.iter_mut() {
+            while let Some(&send_time) = connection.send_times.front() {
+                if now.saturating_sub(send_time) < threshold_ns { break; }
+                connection.send_times.pop_front();
+                dropped += 1;
+            }
+            for times in connection.outstanding.values_mut() {
+                while let Some(&send_time) = times.front() {
+                    if now.saturating_sub(send_time) < threshold_ns { break; }
+                    times.pop_front();
+                    dropped += 1;
+                }
+            }
+        }
+
+        if dropped > 0 {
+            self.udp_dropped += dropped;
+            debug!("reaped {} dropped udp replies ({} total)", dropped, self.udp_dropped);
+        }
+    }
 }
 
 impl Handler for Bench {
-    type Timeout=();
+    type Timeout=TimeoutKind;
     type Message=();
 
     fn ready(&mut self, event_loop: &mut EventLoop<Bench>, token: Token, events: EventSet) {
         debug!("ready, token: {:?}, events: {:?}", token, events);
 
-        if self.count > 0 && self.entries_written > self.count {
+        if self.mode == BenchMode::Put && self.count > 0 && self.entries_written >= self.count {
             exit(0);
         }
 
+        if self.udp {
+            self.udp_ready(event_loop, token, events);
+            return;
+        }
+
         if events.is_error() {
-            panic!("connection error: {:?}", self.connections[token]);
+            self.reconnect(event_loop, token, "errored");
+            return;
         }
         if events.is_hup() {
-            panic!("connection hangup: {:?}", self.connections[token]);
+            self.reconnect(event_loop, token, "hung up");
+            return;
         }
 
         if events.is_readable() {
-            self.readable(token).unwrap();
+            if self.readable(token).is_err() {
+                self.reconnect(event_loop, token, "io error");
+                return;
+            }
         }
 
         if events.is_writable() {
-            self.writable(token).unwrap();
+            if self.writable(event_loop, token).is_err() {
+                self.reconnect(event_loop, token, "io error");
+                return;
+            }
         };
 
         let socket = &self.connections[token].socket;
@@ -181,13 +675,29 @@ impl Handler for Bench {
                               PollOpt::edge() | PollOpt::oneshot()).unwrap();
     }
 
-    fn timeout(&mut self, event_loop: &mut EventLoop<Bench>, _timeout: ()) {
-        self.hist_send.send(mem::replace(&mut self.hist, create_hist())).unwrap();
-        event_loop.timeout_ms((), self.report_duration).unwrap();
+    fn timeout(&mut self, event_loop: &mut EventLoop<Bench>, timeout: TimeoutKind) {
+        match timeout {
+            TimeoutKind::Report => {
+                self.hist_send.send(mem::replace(&mut self.hist, create_hist())).unwrap();
+                if self.udp {
+                    self.udp_reap_drops();
+                }
+                event_loop.timeout_ms(TimeoutKind::Report, self.report_duration).unwrap();
+            },
+            TimeoutKind::Reconnect(token) => self.retry_connect(event_loop, token),
+            TimeoutKind::ResumeWrite(token) => {
+                if self.udp {
+                    if self.udp_connections.get(token).is_some() {
+                        self.udp_writable(event_loop, token).unwrap();
+                    }
+                } else if self.connections.get(token).is_some() {
+                    self.writable(event_loop, token).unwrap();
+                }
+            },
+        }
     }
 }
 
-#[derive(Debug)]
 struct Connection {
     socket: TcpStream,
     /// Holds bytes being read from the socket before deserialization.
@@ -196,20 +706,154 @@ struct Connection {
     write_buf: Vec<u8>,
     bytes_sent: usize,
     send_times: VecDeque<u64>,
+    /// The rustls client session wrapping this connection, when the benchmark was started with
+    /// `--tls`; `None` for a plaintext connection. `read_buf`/`write_buf` always hold plaintext.
+    tls: Option<ClientSession>,
 }
 
 impl Connection {
 
-    /// Creates a new connection with the provided socket.
-    fn new(socket: TcpStream) -> Connection {
+    /// Creates a new connection with the provided socket, wrapped in a rustls client session
+    /// when `tls_config` is set.
+    fn new(socket: TcpStream, tls_config: &Option<Arc<ClientConfig>>) -> Connection {
+        let tls = tls_config.as_ref().map(|config| {
+            let dns_name = webpki::DNSNameRef::try_from_ascii_str("localhost").unwrap();
+            ClientSession::new(config, dns_name)
+        });
         Connection { socket: socket,
                      read_buf: Vec::with_capacity(BUF_SIZE),
                      write_buf: Vec::with_capacity(BUF_SIZE),
                      bytes_sent: 0,
-                     send_times: VecDeque::new() }
+                     send_times: VecDeque::new(),
+                     tls: tls }
+    }
+
+    /// Hands any buffered plaintext request data to the TLS session and flushes its outgoing
+    /// ciphertext through the socket, or, without `--tls`, writes `write_buf` directly to the
+    /// socket. Returns the number of bytes of `write_buf` that are now safe to drain.
+    ///
+    /// With TLS this is always the whole buffer: the session durably buffers whatever ciphertext
+    /// it hasn't yet been able to push to the socket, so handing it the plaintext is enough to
+    /// consider those bytes sent for the purposes of the caller's message-counting.
+    fn flush_write(&mut self) -> Result<usize> {
+        let &mut Connection { ref mut socket, ref mut tls, ref mut write_buf, .. } = self;
+        match tls.as_mut() {
+            Some(tls) => {
+                if !write_buf.is_empty() {
+                    try!(tls.write_all(&write_buf[..]));
+                }
+                while tls.wants_write() {
+                    match tls.write_tls(socket) {
+                        Ok(0) => return Err(Error::new(ErrorKind::WriteZero, "unable to write to socket")),
+                        Ok(_) => (),
+                        Err(ref error) if error.kind() == ErrorKind::WouldBlock => break,
+                        Err(error) => return Err(error),
+                    }
+                }
+                Ok(write_buf.len())
+            },
+            None => {
+                let mut idx = 0;
+                while idx < write_buf.len() {
+                    match socket.write(&write_buf[idx..]) {
+                        Ok(0) => return Err(Error::new(ErrorKind::WriteZero, "unable to write to socket")),
+                        Ok(n) => idx += n,
+                        Err(ref error) if error.kind() == ErrorKind::WouldBlock => break,
+                        Err(error) => return Err(error),
+                    }
+                }
+                Ok(idx)
+            },
+        }
+    }
+
+    /// Reads any bytes available on the socket into `read_buf`, decrypting through the TLS
+    /// session first when one is present.
+    fn fill_read(&mut self) -> Result<()> {
+        let &mut Connection { ref mut socket, ref mut tls, ref mut read_buf, .. } = self;
+        match tls.as_mut() {
+            Some(tls) => {
+                match tls.read_tls(socket) {
+                    Ok(0) => return Ok(()),
+                    Ok(_) => (),
+                    Err(ref error) if error.kind() == ErrorKind::WouldBlock => (),
+                    Err(error) => return Err(error),
+                }
+                if let Err(error) = tls.process_new_packets() {
+                    return Err(Error::new(ErrorKind::InvalidData, format!("tls error: {}", error)));
+                }
+                match tls.read_to_end(read_buf) {
+                    Ok(_) => Ok(()),
+                    Err(ref error) if error.kind() == ErrorKind::WouldBlock => Ok(()),
+                    Err(error) => Err(error),
+                }
+            },
+            None => {
+                match socket.read_to_end(read_buf) {
+                    Ok(_) => Ok(()),
+                    Err(ref error) if error.kind() == ErrorKind::WouldBlock => Ok(()),
+                    Err(error) => Err(error),
+                }
+            },
+        }
+    }
+}
+
+/// Server certificate verifier that accepts anything. `Bench` connects to a known local instance
+/// of `simple-kv` for load testing, not a verified production endpoint, so validating the
+/// certificate chain would only add friction for the self-signed test certificates `--tls` runs
+/// commonly use.
+struct InsecureCertVerifier;
+
+impl rustls::ServerCertVerifier for InsecureCertVerifier {
+    fn verify_server_cert(&self,
+                           _roots: &rustls::RootCertStore,
+                           _presented_certs: &[rustls::Certificate],
+                           _dns_name: webpki::DNSNameRef,
+                           _ocsp_response: &[u8])
+                           -> StdResult<rustls::ServerCertVerified, rustls::TLSError> {
+        Ok(rustls::ServerCertVerified::assertion())
     }
 }
 
+/// Builds the rustls client configuration used for every connection when `--tls` is set.
+fn tls_client_config() -> Arc<ClientConfig> {
+    let mut config = ClientConfig::new();
+    config.dangerous().set_certificate_verifier(Arc::new(InsecureCertVerifier));
+    Arc::new(config)
+}
+
+/// Per-socket UDP connection state. A UDP socket has no byte-stream framing to track, unlike
+/// `Connection`: each request is one outbound datagram, and each reply is one inbound datagram,
+/// though replies may arrive out of order or not at all.
+#[derive(Debug)]
+struct UdpConnection {
+    socket: UdpSocket,
+    /// Send times for requests whose reply carries no identifying payload ("OK"/"ERR" PUT acks),
+    /// matched in send order as a best effort.
+    send_times: VecDeque<u64>,
+    /// Send times for GET requests, keyed by the key index embedded in the value at populate
+    /// time (see `Bench::udp_writable`), so a reply can be matched to its request even when
+    /// replies arrive out of order.
+    outstanding: HashMap<usize, VecDeque<u64>>,
+}
+
+impl UdpConnection {
+    /// Creates a new UDP connection wrapping the provided (already-bound) socket.
+    fn new(socket: UdpSocket) -> UdpConnection {
+        UdpConnection { socket: socket, send_times: VecDeque::new(), outstanding: HashMap::new() }
+    }
+}
+
+/// Parses the key index embedded at the front of a UDP GET response's value (written during
+/// populate; see `Bench::udp_writable`), used to match possibly-reordered replies back to their
+/// request. Returns `None` for a "NONE" response or any value too short to carry an index.
+fn parse_udp_key_index(value: &[u8]) -> Option<usize> {
+    if value.len() < UDP_KEY_INDEX_WIDTH { return None; }
+    str::from_utf8(&value[..UDP_KEY_INDEX_WIDTH]).ok()
+        .and_then(|s| usize::from_str_radix(s, 16).ok())
+}
+
 fn create_hist() -> Histogram {
     Histogram::new(HistogramConfig {
         max_value: 1_000_000_000,
@@ -232,3 +876,69 @@ fn reporter(recv: mpsc::Receiver<Histogram>) {
         mark = now;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mio::tcp::TcpListener;
+
+    /// Regression test for a reconnect that lands in a slab slot other than the one that was
+    /// freed: with more than one slot free, `Slab::insert` is not guaranteed to hand back the
+    /// token the caller just removed, so `retry_connect` must register and track whatever token
+    /// `insert` actually returns rather than assuming it matches the original.
+    #[test]
+    fn retry_connect_registers_whichever_slot_insert_returns() {
+        let bind_addr = SocketAddr::from_str("127.0.0.1:0").unwrap();
+        let listener = TcpListener::bind(&bind_addr).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut event_loop = EventLoop::<Bench>::new().unwrap();
+        let mut connections = Slab::new(4);
+        let mut tokens = Vec::new();
+        for _ in 0..4 {
+            let socket = TcpStream::connect(&addr).unwrap();
+            tokens.push(connections.insert(Connection::new(socket, &None)).unwrap());
+        }
+        // Free two slots, so the slab has more than one candidate for the next
+        // insert and is not guaranteed to hand back the one being reconnected.
+        connections.remove(tokens[0]);
+        connections.remove(tokens[1]);
+
+        let (hist_send, _hist_recv) = mpsc::channel();
+        let mut bench = Bench {
+            connections: connections,
+            addr: addr,
+            mode: BenchMode::Put,
+            phase: Phase::Bench,
+            pid: 0,
+            concurrency: 4,
+            entries_written: 0,
+            entries_acked: 0,
+            count: 0,
+            val_size: 0,
+            batch_size: 0,
+            report_duration: 1000,
+            hist: create_hist(),
+            hist_send: hist_send,
+            rand: XorShiftRng::new_unseeded(),
+            binary: false,
+            reconnects: 0,
+            reconnect_backoff: HashMap::new(),
+            bucket: None,
+            udp: false,
+            udp_connections: Slab::new(0),
+            udp_dropped: 0,
+            tls: false,
+            tls_config: None,
+        };
+
+        bench.retry_connect(&mut event_loop, tokens[0]);
+
+        assert!(!bench.reconnect_backoff.contains_key(&tokens[0]),
+                "successful reconnect must clear the backoff entry for the original token");
+        assert!(bench.connections.get(tokens[2]).is_some(), "untouched slot must be unaffected");
+        assert!(bench.connections.get(tokens[3]).is_some(), "untouched slot must be unaffected");
+        assert!(bench.connections.get(tokens[0]).is_some() || bench.connections.get(tokens[1]).is_some(),
+                "reconnect must land the new connection in one of the freed slots");
+    }
+}
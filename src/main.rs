@@ -8,14 +8,17 @@ extern crate histogram;
 extern crate mio;
 extern crate rand;
 extern crate rustc_serialize;
+extern crate rustls;
 extern crate time;
+extern crate webpki;
 
 mod server;
 mod bench;
+mod protocol;
 
 use docopt::Docopt;
 use server::Server;
-use bench::Bench;
+use bench::{Bench, BenchMode};
 
 const USAGE: &'static str = "
 A simple in-memory Key Value store.
@@ -29,8 +32,9 @@ Commands:
   bench-put     Starts a write benchmark against a rust-db instance.
 
 Usage:
-  simple-kv server [--port=<port>]
-  simple-kv bench  <pid> [--port=<port> --concurrency=<concurrency> --key-size=<key-size> --val-size=<val-size> --batch-size=<batch-size> --report-duration=<report-duration> --count=<count>]
+  simple-kv server [--port=<port> --binary --idle-timeout=<idle-timeout> --udp --tls --cert=<cert> --key=<key>]
+  simple-kv bench-put <pid> [--port=<port> --concurrency=<concurrency> --key-size=<key-size> --val-size=<val-size> --batch-size=<batch-size> --report-duration=<report-duration> --count=<count> --binary --target-qps=<target-qps> --udp --tls]
+  simple-kv bench-get <pid> [--port=<port> --concurrency=<concurrency> --key-size=<key-size> --val-size=<val-size> --batch-size=<batch-size> --report-duration=<report-duration> --count=<count> --binary --target-qps=<target-qps> --udp --tls]
 
 Options:
   -h --help                             Show a help message.
@@ -40,12 +44,20 @@ Options:
   --batch-size=<batch-size>             How many key-value pairs to write per connection per event-loop tick [default: 10].
   --report-duration=<report-duration>   How often to report results, in ms [default: 1000].
   --count=<count>                       Number of KV entries to write, or unlimited if 0 [default: 0].
+  --binary                              Use length-prefixed binary framing instead of the text protocol.
+  --target-qps=<target-qps>             Cap offered load to this many ops/sec across all connections, or unlimited if 0 [default: 0].
+  --idle-timeout=<idle-timeout>         Evict connections idle for longer than this many ms, or never if 0 [default: 0].
+  --udp                                 Speak UDP datagrams (server: on the same port, alongside the TCP listener; bench: instead of a TCP stream).
+  --tls                                 Wrap the TCP stream in TLS (server: requires --cert and --key; bench: connects without verifying the server's certificate).
+  --cert=<cert>                         Path to a PEM certificate chain for --tls [default: ].
+  --key=<key>                           Path to a PEM private key for --tls [default: ].
 ";
 
 #[derive(Debug, RustcDecodable)]
 struct Args {
     cmd_server: bool,
-    cmd_bench: bool,
+    cmd_bench_get: bool,
+    cmd_bench_put: bool,
 
     arg_pid: i32,
 
@@ -55,6 +67,13 @@ struct Args {
     flag_batch_size: usize,
     flag_report_duration: u64,
     flag_count: usize,
+    flag_binary: bool,
+    flag_target_qps: u64,
+    flag_idle_timeout: u64,
+    flag_udp: bool,
+    flag_tls: bool,
+    flag_cert: String,
+    flag_key: String,
 }
 
 
@@ -66,14 +85,33 @@ fn main() {
                             .unwrap_or_else(|e| e.exit());
 
     if args.cmd_server {
-        Server::start(args.flag_port).unwrap();
-    } else if args.cmd_bench {
-        Bench::start(args.flag_port,
+        Server::start(args.flag_port, args.flag_binary, args.flag_idle_timeout, args.flag_udp,
+                      args.flag_tls, args.flag_cert, args.flag_key).unwrap();
+    } else if args.cmd_bench_put {
+        Bench::start(BenchMode::Put,
+                     args.flag_port,
                      args.arg_pid,
                      args.flag_concurrency,
                      args.flag_val_size,
                      args.flag_batch_size,
                      args.flag_count,
-                     args.flag_report_duration).unwrap();
+                     args.flag_report_duration,
+                     args.flag_binary,
+                     args.flag_target_qps,
+                     args.flag_udp,
+                     args.flag_tls).unwrap();
+    } else if args.cmd_bench_get {
+        Bench::start(BenchMode::Get,
+                     args.flag_port,
+                     args.arg_pid,
+                     args.flag_concurrency,
+                     args.flag_val_size,
+                     args.flag_batch_size,
+                     args.flag_count,
+                     args.flag_report_duration,
+                     args.flag_binary,
+                     args.flag_target_qps,
+                     args.flag_udp,
+                     args.flag_tls).unwrap();
     }
 }
@@ -0,0 +1,113 @@
+//! Binary, length-prefixed wire framing shared by the server and the benchmark client.
+//!
+//! Each request frame is a 4-byte big-endian total length followed by a 1-byte opcode
+//! (`OP_GET` or `OP_PUT`), then for GET a 2-byte key length and the key bytes, and for PUT a
+//! 2-byte key length, key bytes, 4-byte value length, and value bytes. Responses are
+//! length-prefixed in turn: a 4-byte length plus the raw value for GET (empty length meaning
+//! "not found"), or a single status byte for PUT.
+//!
+//! This framing is opt-in (negotiated by the server's `--binary` flag) and exists alongside the
+//! original whitespace/newline text protocol, which remains the default.
+
+/// Opcode for a GET request frame.
+pub const OP_GET: u8 = 0;
+/// Opcode for a PUT request frame.
+pub const OP_PUT: u8 = 1;
+
+/// PUT response status: applied successfully.
+pub const STATUS_OK: u8 = 0;
+/// PUT response status: the request could not be decoded.
+pub const STATUS_ERR: u8 = 1;
+
+pub fn write_u16_be(buf: &mut Vec<u8>, n: u16) {
+    buf.push((n >> 8) as u8);
+    buf.push(n as u8);
+}
+
+pub fn write_u32_be(buf: &mut Vec<u8>, n: u32) {
+    buf.push((n >> 24) as u8);
+    buf.push((n >> 16) as u8);
+    buf.push((n >> 8) as u8);
+    buf.push(n as u8);
+}
+
+pub fn read_u16_be(bytes: &[u8]) -> u16 {
+    ((bytes[0] as u16) << 8) | bytes[1] as u16
+}
+
+pub fn read_u32_be(bytes: &[u8]) -> u32 {
+    ((bytes[0] as u32) << 24) | ((bytes[1] as u32) << 16) | ((bytes[2] as u32) << 8) | bytes[3] as u32
+}
+
+/// Encodes a GET request frame (length prefix included) onto the end of `buf`.
+pub fn encode_get(buf: &mut Vec<u8>, key: &[u8]) {
+    let body_len = 1 + 2 + key.len();
+    write_u32_be(buf, body_len as u32);
+    buf.push(OP_GET);
+    write_u16_be(buf, key.len() as u16);
+    buf.extend(key);
+}
+
+/// Encodes a PUT request frame (length prefix included) onto the end of `buf`.
+pub fn encode_put(buf: &mut Vec<u8>, key: &[u8], value: &[u8]) {
+    let body_len = 1 + 2 + key.len() + 4 + value.len();
+    write_u32_be(buf, body_len as u32);
+    buf.push(OP_PUT);
+    write_u16_be(buf, key.len() as u16);
+    buf.extend(key);
+    write_u32_be(buf, value.len() as u32);
+    buf.extend(value);
+}
+
+/// Encodes a GET response: the value's length and bytes, or a zero length for "not found".
+pub fn encode_get_response(buf: &mut Vec<u8>, value: Option<&[u8]>) {
+    match value {
+        Some(v) => {
+            write_u32_be(buf, v.len() as u32);
+            buf.extend(v);
+        },
+        None => write_u32_be(buf, 0),
+    }
+}
+
+/// Encodes a PUT response: a single status byte.
+pub fn encode_put_response(buf: &mut Vec<u8>, ok: bool) {
+    buf.push(if ok { STATUS_OK } else { STATUS_ERR });
+}
+
+/// Counts and consumes complete 1-byte PUT-status response frames buffered in `buf`.
+pub fn count_status_responses(buf: &mut Vec<u8>) -> usize {
+    let n = buf.len();
+    buf.clear();
+    n
+}
+
+/// Counts complete length-prefixed request frames in the leading `bytes`, leaving any trailing
+/// partial frame uncounted. Used to determine how many whole requests a partial socket write
+/// actually landed on the wire, since the write may split a frame at any byte boundary.
+pub fn count_frames(bytes: &[u8]) -> usize {
+    let mut pos = 0;
+    let mut count = 0;
+    while bytes.len() - pos >= 4 {
+        let len = read_u32_be(&bytes[pos..pos + 4]) as usize;
+        if bytes.len() - pos < 4 + len { break; }
+        pos += 4 + len;
+        count += 1;
+    }
+    count
+}
+
+/// Counts and consumes complete length-prefixed GET response frames buffered in `buf`, leaving
+/// any trailing partial frame in place for the next read.
+pub fn count_value_responses(buf: &mut Vec<u8>) -> usize {
+    let mut pos = 0;
+    let mut count = 0;
+    while buf.len() - pos >= 4 {
+        let len = read_u32_be(&buf[pos..pos + 4]) as usize;
+        if buf.len() - pos < 4 + len { break; }
+        pos += 4 + len;
+        count += 1;
+    }
+    buf.drain(..pos);
+    count
+}
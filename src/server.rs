@@ -1,63 +1,253 @@
-use std::collections::HashMap;
-use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{BufReader, Error, ErrorKind, Read, Result, Write};
+use std::iter;
+use std::mem;
 use std::net::SocketAddr;
 use std::str::{self, FromStr};
+use std::sync::Arc;
 
 use mio::{PollOpt, EventLoop, EventSet, Handler, Token};
 use mio::tcp::{TcpListener, TcpStream};
+use mio::udp::UdpSocket;
 use mio::util::Slab;
+use rustls::{self, NoClientAuth, ServerConfig, ServerSession, Session};
+use time;
+
+use protocol;
 
 const LISTENER: Token = Token(0);
+/// Token for the optional `--udp` datagram socket, which lives alongside (not instead of) the
+/// TCP listener; the connection `Slab` is started one token higher to make room for it.
+const UDP_SOCKET: Token = Token(1);
+/// Largest possible UDP datagram payload.
+const MAX_UDP_DATAGRAM: usize = 65_507;
 
 /// Initial read and write buffer size.
 const BUF_SIZE: usize = 128;
 /// Maximum number of concurrent clients.
 const SLAB_SIZE: usize = 4096;
+/// Maximum accepted binary frame body length; guards against a bogus or hostile length prefix
+/// pinning an unbounded amount of memory in `read_buf`.
+const MAX_FRAME_LEN: u32 = 16 * 1024 * 1024;
+/// Number of buckets in the idle-connection timing wheel. The sweep fires every
+/// `idle_timeout / WHEEL_BUCKETS`, and a connection survives a full trip around the wheel
+/// (`WHEEL_BUCKETS` sweeps) without activity before it is evicted; this bounds the per-tick
+/// eviction scan to whichever bucket is due, rather than scanning every connection.
+const WHEEL_BUCKETS: usize = 4;
 
 /// A simple Key/Value database server.
 ///
-/// The server listens for text-based TCP messages in the following formats:
+/// The server listens for either a text-based protocol or, when started with `--binary`, a
+/// length-prefixed binary protocol; see `Framing` and the `protocol` module, respectively. When
+/// started with `--udp`, it additionally accepts the text protocol over UDP datagrams on the same
+/// port, one request per datagram, with no per-client connection state. When started with
+/// `--tls`, TCP connections are wrapped in a rustls server session (see `Connection::tls`) so the
+/// wire bytes are encrypted; UDP traffic is unaffected.
+///
+/// The text protocol uses the following formats:
 ///
 /// * 'PUT <key> <value>'
 /// * 'GET <key>'
 pub struct Server {
     listener: TcpListener,
     connections: Slab<Connection>,
-    db: HashMap<String, String>,
+    db: HashMap<Vec<u8>, Vec<u8>>,
+    binary: bool,
+    /// Idle-connection eviction threshold, in milliseconds; disabled when zero.
+    idle_timeout: u64,
+    /// Timing wheel used to find idle connections without scanning the whole `Slab` each sweep.
+    /// Every connection lives in exactly one bucket, indexed by when it will next be swept; a
+    /// readable/writable event moves it into the bucket currently under the wheel's hand.
+    wheel: Vec<Vec<Token>>,
+    /// The bucket most recently swept (and where freshly-touched connections are placed).
+    wheel_pos: usize,
+    /// The `--udp` datagram socket, when enabled; `None` otherwise.
+    udp_socket: Option<UdpSocket>,
+    /// Responses awaiting a chance to be sent back over `udp_socket`, flushed on writable events
+    /// (there is no per-client slab to buffer against, since UDP is connectionless).
+    udp_outbox: VecDeque<(SocketAddr, Vec<u8>)>,
+    /// TLS configuration loaded from `--cert`/`--key`, when `--tls` is enabled; `None` otherwise.
+    /// Shared (via `Arc`) with every `ServerSession` created for an accepted connection.
+    tls_config: Option<Arc<ServerConfig>>,
 }
 
 impl Server {
-    pub fn start(port: u32) -> Result<()> {
+    pub fn start(port: u32, binary: bool, idle_timeout: u64, udp: bool,
+                 tls: bool, cert_path: String, key_path: String) -> Result<()> {
         let mut event_loop = try!(EventLoop::<Server>::new());
-        info!("Starting simple-kv Rust server with listening port {}", port);
+        info!("Starting simple-kv Rust server with listening port {} ({}){}{}{}",
+              port,
+              if binary { "binary" } else { "text" },
+              if idle_timeout > 0 {
+                  format!(", idle timeout {}ms", idle_timeout)
+              } else {
+                  String::new()
+              },
+              if udp { ", udp" } else { "" },
+              if tls { ", tls" } else { "" });
         let addr = SocketAddr::from_str(&format!("127.0.0.1:{}", port)).unwrap();
         let listener = try!(TcpListener::bind(&addr));
         try!(event_loop.register(&listener, LISTENER));
 
+        let udp_socket = if udp {
+            let socket = try!(UdpSocket::bound(&addr));
+            try!(event_loop.register_opt(&socket, UDP_SOCKET, EventSet::readable(), PollOpt::edge()));
+            Some(socket)
+        } else {
+            None
+        };
+
+        let tls_config = if tls {
+            Some(Arc::new(try!(load_tls_config(&cert_path, &key_path))))
+        } else {
+            None
+        };
+
         let mut server = Server { listener: listener,
-                                  connections: Slab::new_starting_at(Token(1), SLAB_SIZE),
-                                  db: HashMap::new() };
+                                  connections: Slab::new_starting_at(Token(2), SLAB_SIZE),
+                                  db: HashMap::new(),
+                                  binary: binary,
+                                  idle_timeout: idle_timeout,
+                                  wheel: iter::repeat(Vec::new()).take(WHEEL_BUCKETS).collect(),
+                                  wheel_pos: 0,
+                                  udp_socket: udp_socket,
+                                  udp_outbox: VecDeque::new(),
+                                  tls_config: tls_config };
+
+        if idle_timeout > 0 {
+            let interval = cmp::max(idle_timeout / WHEEL_BUCKETS as u64, 1);
+            event_loop.timeout_ms((), interval).unwrap();
+        }
 
         event_loop.run(&mut server)
     }
 
+    /// Reads and applies all datagrams currently pending on the UDP socket, queueing a response
+    /// datagram for each back to its sender.
+    fn udp_readable(&mut self) {
+        let &mut Server { ref udp_socket, ref mut db, ref mut udp_outbox, .. } = self;
+        let udp_socket = match *udp_socket {
+            Some(ref socket) => socket,
+            None => return,
+        };
+
+        let mut buf = [0u8; MAX_UDP_DATAGRAM];
+        loop {
+            match udp_socket.recv_from(&mut buf) {
+                Ok(Some((len, src))) => {
+                    let message = Message::from_text(&buf[..len]);
+                    debug!("received udp message from {}: {:?}", src, message);
+                    let response = match message {
+                        Message::Get(key) => db.get(&key).cloned().unwrap_or_else(|| b"NONE".to_vec()),
+                        Message::Put(key, value) => { db.insert(key, value); b"OK".to_vec() },
+                        Message::Error => b"ERR".to_vec(),
+                    };
+                    udp_outbox.push_back((src, response));
+                },
+                Ok(None) => break,
+                Err(ref error) if error.kind() == ErrorKind::WouldBlock => break,
+                Err(error) => { warn!("error reading udp datagram: {}", error); break; },
+            }
+        }
+    }
+
+    /// Flushes as much of the UDP outbox as the socket will currently accept, re-registering for
+    /// writable events if backpressure leaves datagrams queued.
+    fn udp_flush(&mut self, event_loop: &mut EventLoop<Server>) {
+        let &mut Server { ref udp_socket, ref mut udp_outbox, .. } = self;
+        let udp_socket = match *udp_socket {
+            Some(ref socket) => socket,
+            None => return,
+        };
+
+        while let Some((addr, datagram)) = udp_outbox.pop_front() {
+            match udp_socket.send_to(&datagram, &addr) {
+                Ok(Some(_)) => (),
+                Ok(None) => {
+                    udp_outbox.push_front((addr, datagram));
+                    break;
+                },
+                Err(ref error) if error.kind() == ErrorKind::WouldBlock => {
+                    udp_outbox.push_front((addr, datagram));
+                    break;
+                },
+                Err(error) => warn!("error sending udp datagram to {}: {}", addr, error),
+            }
+        }
+
+        let events = if udp_outbox.is_empty() {
+            EventSet::readable()
+        } else {
+            EventSet::readable() | EventSet::writable()
+        };
+        if let Err(error) = event_loop.reregister(udp_socket, UDP_SOCKET, events, PollOpt::edge()) {
+            warn!("error reregistering udp socket: {}", error);
+        }
+    }
+
     /// Called when the TCP listener accepts a new socket connection.
     fn accept_connections(&mut self, event_loop: &mut EventLoop<Server>) {
         while let Ok(Some(socket)) = self.listener.accept() {
             info!("new connection accepted from {}", socket.peer_addr().unwrap());
-            if let Ok(token) = self.connections.insert(Connection::new(socket)) {
+            let framing = if self.binary { Framing::Binary } else { Framing::Text };
+            let wheel_pos = self.wheel_pos;
+            let now = time::precise_time_ns();
+            let connection = Connection::new(socket, framing, wheel_pos, now, &self.tls_config);
+            let events = connection.events;
+            if let Ok(token) = self.connections.insert(connection) {
+                if self.idle_timeout > 0 {
+                    self.wheel[wheel_pos].push(token);
+                }
                 event_loop.register_opt(&self.connections[token].socket,
                                         token,
-                                        EventSet::readable(),
+                                        events,
                                         PollOpt::edge() | PollOpt::oneshot())
                           .unwrap_or_else(|error| {
                               warn!("unable to register accepted socket: {}", error);
-                              self.connections.remove(token);
+                              self.remove_connection(token);
                           });
             }
         }
     }
 
+    /// Removes `token`'s connection, first removing it from its timing-wheel bucket. Every
+    /// removal outside the sweep itself (`timeout`, which already drains the token out of its
+    /// bucket before removing) must go through this rather than `self.connections.remove(token)`
+    /// directly, or the stale token is left in `wheel` until the sweep reaches that bucket, by
+    /// which point the slab slot may have been reused by an unrelated connection.
+    fn remove_connection(&mut self, token: Token) {
+        if self.idle_timeout > 0 {
+            if let Some(connection) = self.connections.get(token) {
+                let bucket = connection.bucket;
+                if let Some(i) = self.wheel[bucket].iter().position(|&t| t == token) {
+                    self.wheel[bucket].swap_remove(i);
+                }
+            }
+        }
+        self.connections.remove(token);
+    }
+
+    /// Stamps `token`'s connection as active and moves it into the current wheel bucket, so
+    /// that the idle sweep does not evict it.
+    fn touch(&mut self, token: Token) {
+        if self.idle_timeout == 0 { return; }
+        let now = time::precise_time_ns();
+        let &mut Server { ref mut connections, ref mut wheel, wheel_pos, .. } = self;
+
+        if let Some(connection) = connections.get_mut(token) {
+            if connection.bucket != wheel_pos {
+                if let Some(i) = wheel[connection.bucket].iter().position(|&t| t == token) {
+                    wheel[connection.bucket].swap_remove(i);
+                }
+                wheel[wheel_pos].push(token);
+                connection.bucket = wheel_pos;
+            }
+            connection.last_active = now;
+        }
+    }
+
     /// Called when a connection is readable.
     fn connection_readable(&mut self, token: Token) -> Result<()> {
         let &mut Server { ref mut connections, ref mut db, .. } = self;
@@ -67,15 +257,24 @@ impl Server {
             debug!("received message from {:?}: {:?}", token, message);
             match message {
                 Message::Get(key) => {
-                    let val = db.get(&key).map(|s| &s[..]).unwrap_or("NONE");
-                    connection.send(val);
+                    let value = db.get(&key).map(|v| &v[..]);
+                    match connection.framing {
+                        Framing::Text => connection.send_line(value.unwrap_or(b"NONE")),
+                        Framing::Binary => connection.send_binary_value(value),
+                    }
                 },
                 Message::Put(key, value) => {
-                    db.insert(key.to_owned(), value.to_owned());
-                    connection.send("OK");
+                    db.insert(key, value);
+                    match connection.framing {
+                        Framing::Text => connection.send_line(b"OK"),
+                        Framing::Binary => connection.send_binary_status(true),
+                    }
                 },
                 Message::Error => {
-                    connection.send("ERR");
+                    match connection.framing {
+                        Framing::Text => connection.send_line(b"ERR"),
+                        Framing::Binary => connection.send_binary_status(false),
+                    }
                 },
             }
         };
@@ -94,58 +293,103 @@ impl Handler for Server {
             assert!(events == EventSet::readable(),
                     "unexpected events for listener: {:?}", events);
             self.accept_connections(event_loop);
+        } else if token == UDP_SOCKET {
+            if events.is_readable() {
+                self.udp_readable();
+            }
+            self.udp_flush(event_loop);
         } else {
 
             if events.is_error() {
                 warn!("connection error: {:?}", token);
-                self.connections.remove(token);
+                self.remove_connection(token);
                 return;
             }
 
             if events.is_hup() {
                 debug!("connection hangup: {:?}", token);
-                self.connections.remove(token);
+                self.remove_connection(token);
                 return;
             }
 
             if events.is_readable() {
                 if let Err(error) = self.connection_readable(token) {
                     warn!("error while reading from {:?}: {}", token, error);
-                    self.connections.remove(token);
+                    self.remove_connection(token);
                     return;
                 }
+                self.touch(token);
             }
 
             if events.is_writable() {
                 if let Err(error) = self.connections[token].writable() {
                     warn!("error while writing to {:?}: {}", token, error);
-                    self.connections.remove(token);
+                    self.remove_connection(token);
                     return
                 }
+                self.touch(token);
             }
 
             let events = self.connections[token].events;
             if let Err(error) = event_loop.reregister(&mut self.connections[token].socket, token,
                                                       events, PollOpt::edge() | PollOpt::oneshot()) {
                 warn!("error while reregistering connection: {}", error);
+                self.remove_connection(token);
+            }
+        }
+    }
+
+    /// Idle-connection sweep: advances the timing wheel one bucket and evicts any connection
+    /// that has sat there, untouched, for a full trip around the wheel.
+    fn timeout(&mut self, event_loop: &mut EventLoop<Server>, _timeout: ()) {
+        self.wheel_pos = (self.wheel_pos + 1) % WHEEL_BUCKETS;
+        let wheel_pos = self.wheel_pos;
+        let due = mem::replace(&mut self.wheel[wheel_pos], Vec::new());
+        let now = time::precise_time_ns();
+        let threshold_ns = self.idle_timeout * 1_000_000;
+
+        for token in due {
+            let idle_ns = match self.connections.get(token) {
+                Some(connection) => now.saturating_sub(connection.last_active),
+                None => continue, // already removed, e.g. by an earlier error or hangup.
+            };
+
+            if idle_ns >= threshold_ns {
+                debug!("evicting idle connection {:?} ({}ms idle)", token, idle_ns / 1_000_000);
+                event_loop.deregister(&self.connections[token].socket).ok();
                 self.connections.remove(token);
+            } else {
+                self.connections[token].bucket = wheel_pos;
+                self.wheel[wheel_pos].push(token);
             }
         }
+
+        let interval = cmp::max(self.idle_timeout / WHEEL_BUCKETS as u64, 1);
+        event_loop.timeout_ms((), interval).unwrap();
     }
 }
 
+/// Which wire framing a connection was accepted under.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Framing {
+    /// Whitespace/newline-delimited text protocol.
+    Text,
+    /// Length-prefixed binary protocol; see the `protocol` module.
+    Binary,
+}
+
 #[derive(Debug)]
 enum Message {
     /// A get message, including the key to look up.
-    Get(String),
+    Get(Vec<u8>),
     /// A put message, including the key and value.
-    Put(String, String),
+    Put(Vec<u8>, Vec<u8>),
     /// Unable to decode the message
     Error,
 }
 
 impl Message {
-    fn from_bytes(bytes: &[u8]) -> Message {
+    fn from_text(bytes: &[u8]) -> Message {
         let line = match str::from_utf8(bytes) {
             Ok(chars) => chars,
             Err(..) => { info!("error: decode"); return Message::Error},
@@ -157,15 +401,50 @@ impl Message {
         match words[0] {
             "GET" => {
                 if len != 2 { info!("error: len != 2"); Message::Error }
-                else { Message::Get(words[1].to_owned()) }
+                else { Message::Get(words[1].as_bytes().to_vec()) }
             },
             "PUT" => {
-                if len != 3 { info!("error: len != 3, '{}'", str::from_utf8(bytes).unwrap()); Message::Error }
-                else { Message::Put(words[1].to_owned(), words[2].to_owned()) }
+                if len != 3 { info!("error: len != 3, '{}'", line); Message::Error }
+                else { Message::Put(words[1].as_bytes().to_vec(), words[2].as_bytes().to_vec()) }
             },
             _ => {info!("unknown command: {}", words[0]); Message::Error},
         }
     }
+
+    /// Decodes a single binary request frame's body (the bytes following the 4-byte length
+    /// prefix, as buffered by `Connection::readable`).
+    fn from_binary(frame: &[u8]) -> Message {
+        if frame.is_empty() { info!("error: empty frame"); return Message::Error; }
+        match frame[0] {
+            protocol::OP_GET => {
+                if frame.len() < 3 { info!("error: truncated GET frame"); return Message::Error; }
+                let key_len = protocol::read_u16_be(&frame[1..3]) as usize;
+                if frame.len() != 3 + key_len {
+                    info!("error: GET frame length mismatch");
+                    return Message::Error;
+                }
+                Message::Get(frame[3..3 + key_len].to_vec())
+            },
+            protocol::OP_PUT => {
+                if frame.len() < 3 { info!("error: truncated PUT frame"); return Message::Error; }
+                let key_len = protocol::read_u16_be(&frame[1..3]) as usize;
+                let val_len_off = 3 + key_len;
+                if frame.len() < val_len_off + 4 {
+                    info!("error: truncated PUT frame");
+                    return Message::Error;
+                }
+                let val_len = protocol::read_u32_be(&frame[val_len_off..val_len_off + 4]) as usize;
+                if frame.len() != val_len_off + 4 + val_len {
+                    info!("error: PUT frame length mismatch");
+                    return Message::Error;
+                }
+                let key = frame[3..val_len_off].to_vec();
+                let value = frame[val_len_off + 4..].to_vec();
+                Message::Put(key, value)
+            },
+            opcode => { info!("unknown opcode: {}", opcode); Message::Error },
+        }
+    }
 }
 
 struct Connection {
@@ -176,62 +455,164 @@ struct Connection {
     write_buf: Vec<u8>,
     /// The set of events which the connection is registerd to handle.
     events: EventSet,
+    /// The wire framing this connection was accepted under.
+    framing: Framing,
+    /// When this connection last saw a readable or writable event, per `time::precise_time_ns`.
+    last_active: u64,
+    /// The idle-eviction timing-wheel bucket this connection currently lives in.
+    bucket: usize,
+    /// The rustls server session wrapping this connection, when the server was started with
+    /// `--tls`; `None` for a plaintext connection. `read_buf`/`write_buf` always hold plaintext
+    /// bytes, before encryption or after decryption; the session owns the encrypted stream.
+    tls: Option<ServerSession>,
 }
 
 impl Connection {
 
-    /// Creates a new connection with the provided socket.
-    fn new(socket: TcpStream) -> Connection {
+    /// Creates a new connection with the provided socket, placed in timing-wheel `bucket`. When
+    /// `tls_config` is set, the connection is wrapped in a fresh `ServerSession` and registered
+    /// for writable events in addition to readable, since the handshake requires writing before
+    /// any plaintext can be exchanged.
+    fn new(socket: TcpStream, framing: Framing, bucket: usize, now: u64,
+           tls_config: &Option<Arc<ServerConfig>>) -> Connection {
+        let tls = tls_config.as_ref().map(|config| ServerSession::new(config));
+        let mut events = EventSet::readable() | EventSet::hup() | EventSet::error();
+        if tls.is_some() {
+            events.insert(EventSet::writable());
+        }
         Connection { socket: socket,
                      read_buf: Vec::with_capacity(BUF_SIZE),
                      write_buf: Vec::with_capacity(BUF_SIZE),
-                     events: EventSet::readable() | EventSet::hup() | EventSet::error() }
+                     events: events,
+                     framing: framing,
+                     last_active: now,
+                     bucket: bucket,
+                     tls: tls }
     }
 
     /// Called when there are bytes available to read on the connection's socket.
     ///
     /// Returns the messages read.
     fn readable(&mut self) -> Result<Vec<Message>> {
-        let mut messages = Vec::new();
-        let read_buf = &mut self.read_buf;
+        if self.tls.is_some() {
+            try!(self.tls_readable());
+            self.update_tls_events();
+        } else {
+            match self.socket.read_to_end(&mut self.read_buf) {
+                Ok(0) => return Ok(Vec::new()),
+                Ok(_) => (),
+                Err(ref error) if error.kind() == ErrorKind::WouldBlock => (),
+                Err(error) => return Err(error),
+            }
+        }
+
+        match self.framing {
+            Framing::Text => Ok(self.readable_text()),
+            Framing::Binary => Ok(self.readable_binary()),
+        }
+    }
 
-        let read_from = read_buf.len();
-        match self.socket.read_to_end(read_buf) {
-            Ok(0) => return Ok(messages),
+    /// Feeds raw socket bytes into the TLS session, drives `process_new_packets` to advance the
+    /// handshake and/or decrypt application data, and appends any newly decrypted plaintext onto
+    /// `read_buf` for `readable_text`/`readable_binary` to decode exactly as they would without
+    /// TLS.
+    fn tls_readable(&mut self) -> Result<()> {
+        let &mut Connection { ref mut socket, ref mut tls, ref mut read_buf, .. } = self;
+        let tls = tls.as_mut().unwrap();
+
+        match tls.read_tls(socket) {
+            Ok(0) => return Ok(()),
             Ok(_) => (),
             Err(ref error) if error.kind() == ErrorKind::WouldBlock => (),
             Err(error) => return Err(error),
         }
 
+        if let Err(error) = tls.process_new_packets() {
+            return Err(Error::new(ErrorKind::InvalidData, format!("tls error: {}", error)));
+        }
+
+        match tls.read_to_end(read_buf) {
+            Ok(_) => (),
+            Err(ref error) if error.kind() == ErrorKind::WouldBlock => (),
+            Err(error) => return Err(error),
+        }
+
+        Ok(())
+    }
+
+    /// Decodes newline-delimited text messages out of `read_buf`, leaving any trailing partial
+    /// line buffered for the next read.
+    fn readable_text(&mut self) -> Vec<Message> {
+        let mut messages = Vec::new();
+        let read_buf = &mut self.read_buf;
+
         let mut lo = 0;
-        // Check the newly read bytes for line seperators. For each line, decode it
-        // into a message and add it to the messages list.
-        for (hi, &c) in read_buf[read_from..].iter().enumerate() {
+        for (hi, &c) in read_buf.iter().enumerate() {
             if c == '\n' as u8 {
-                let line = &read_buf[lo..hi];
-                messages.push(Message::from_bytes(line));
+                messages.push(Message::from_text(&read_buf[lo..hi]));
                 lo = hi + 1;
             }
         }
 
-        // Remove bytes that have been decoded into lines.
         read_buf.drain(..lo).count();
-        Ok(messages)
+        messages
     }
 
-    /// Adds the message to the connection's send buffer, to be sent the next time the socket is
-    /// writable.
-    fn send(&mut self, message: &str) {
-        // Ensure we are listening for writable events on this connection.
-        self.events.insert(EventSet::writable());
+    /// Decodes length-prefixed binary frames out of `read_buf`, leaving any trailing partial
+    /// frame buffered for the next read. A bogus or oversized length prefix yields a single
+    /// `Message::Error` and discards the rest of the buffer, since framing is lost at that point.
+    fn readable_binary(&mut self) -> Vec<Message> {
+        let mut messages = Vec::new();
+        let mut pos = 0;
+
+        loop {
+            if self.read_buf.len() - pos < 4 { break; }
+            let len = protocol::read_u32_be(&self.read_buf[pos..pos + 4]);
+            if len > MAX_FRAME_LEN {
+                warn!("oversized frame length {}; dropping connection buffer", len);
+                messages.push(Message::Error);
+                pos = self.read_buf.len();
+                break;
+            }
+            let len = len as usize;
+            if self.read_buf.len() - pos < 4 + len { break; }
+            let frame_start = pos + 4;
+            messages.push(Message::from_binary(&self.read_buf[frame_start..frame_start + len]));
+            pos = frame_start + len;
+        }
+
+        self.read_buf.drain(..pos).count();
+        messages
+    }
 
-        // Write the message into the buffer.
-        self.write_buf.extend(message.as_bytes().iter());
+    /// Adds a newline-terminated text response to the connection's send buffer.
+    fn send_line(&mut self, message: &[u8]) {
+        self.events.insert(EventSet::writable());
+        self.write_buf.extend(message.iter());
         self.write_buf.push('\n' as u8);
     }
 
+    /// Adds a binary GET response (length-prefixed value, or zero-length for "not found") to the
+    /// connection's send buffer.
+    fn send_binary_value(&mut self, value: Option<&[u8]>) {
+        self.events.insert(EventSet::writable());
+        protocol::encode_get_response(&mut self.write_buf, value);
+    }
+
+    /// Adds a binary PUT response (a single status byte) to the connection's send buffer.
+    fn send_binary_status(&mut self, ok: bool) {
+        self.events.insert(EventSet::writable());
+        protocol::encode_put_response(&mut self.write_buf, ok);
+    }
+
     /// Called when the connection's socket is writeable.
     fn writable(&mut self) -> Result<()> {
+        if self.tls.is_some() {
+            try!(self.tls_writable());
+            self.update_tls_events();
+            return Ok(());
+        }
+
         let mut idx = 0; // index of last written byte in the write buffer.
         while idx < self.write_buf.len() {
             match self.socket.write(&self.write_buf[idx..]) {
@@ -250,4 +631,127 @@ impl Connection {
         self.write_buf.clear();
         Ok(())
     }
+
+    /// Hands any buffered plaintext response to the TLS session and flushes its outgoing
+    /// ciphertext through the socket, stopping (not erroring) on `WouldBlock` exactly as the
+    /// plaintext `writable` loop does; the session keeps whatever it could not yet send
+    /// buffered internally for the next writable event.
+    fn tls_writable(&mut self) -> Result<()> {
+        let &mut Connection { ref mut socket, ref mut tls, ref mut write_buf, .. } = self;
+        let tls = tls.as_mut().unwrap();
+
+        if !write_buf.is_empty() {
+            try!(tls.write_all(&write_buf[..]));
+            write_buf.clear();
+        }
+
+        while tls.wants_write() {
+            match tls.write_tls(socket) {
+                Ok(0) => return Err(Error::new(ErrorKind::WriteZero, "unable to write to socket")),
+                Ok(_) => (),
+                Err(ref error) if error.kind() == ErrorKind::WouldBlock => break,
+                Err(error) => return Err(error),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Recomputes the writable-interest bit for a TLS connection. While the handshake is still in
+    /// progress, or the session has ciphertext it couldn't finish flushing, it needs writable
+    /// events even with an empty `write_buf`; a plaintext connection's interest is driven solely
+    /// by whether `write_buf` holds unsent bytes (see `send_line` etc.) and needs no such check.
+    fn update_tls_events(&mut self) {
+        let wants_write = {
+            let tls = self.tls.as_ref().unwrap();
+            tls.wants_write() || tls.is_handshaking()
+        };
+        if wants_write {
+            self.events.insert(EventSet::writable());
+        } else {
+            self.events.remove(EventSet::writable());
+        }
+    }
+}
+
+/// Loads a rustls server configuration from a PEM certificate chain (`cert_path`) and PEM private
+/// key (`key_path`), as named by `--cert`/`--key`.
+fn load_tls_config(cert_path: &str, key_path: &str) -> Result<ServerConfig> {
+    let cert_file = try!(File::open(cert_path));
+    let certs = match rustls::internal::pemfile::certs(&mut BufReader::new(cert_file)) {
+        Ok(certs) => certs,
+        Err(_) => return Err(Error::new(ErrorKind::InvalidData,
+                                         format!("unable to parse certificate PEM at {}", cert_path))),
+    };
+
+    let key_file = try!(File::open(key_path));
+    let mut keys = match rustls::internal::pemfile::rsa_private_keys(&mut BufReader::new(key_file)) {
+        Ok(keys) => keys,
+        Err(_) => return Err(Error::new(ErrorKind::InvalidData,
+                                         format!("unable to parse private key PEM at {}", key_path))),
+    };
+    if keys.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidData,
+                               format!("no private keys found in {}", key_path)));
+    }
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    try!(config.set_single_cert(certs, keys.remove(0))
+               .map_err(|error| Error::new(ErrorKind::InvalidData,
+                                            format!("invalid certificate/key: {:?}", error))));
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    /// Accepts one loopback connection, returning the server-side socket. `TcpStream::connect`
+    /// on a non-blocking listener does not complete synchronously with the accept, so this polls
+    /// briefly rather than assuming the first `accept()` call sees it.
+    fn accept_loopback() -> TcpStream {
+        let bind_addr = SocketAddr::from_str("127.0.0.1:0").unwrap();
+        let listener = TcpListener::bind(&bind_addr).unwrap();
+        let _client = TcpStream::connect(&listener.local_addr().unwrap()).unwrap();
+        loop {
+            if let Some(socket) = listener.accept().unwrap() {
+                return socket;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn test_server() -> Server {
+        let bind_addr = SocketAddr::from_str("127.0.0.1:0").unwrap();
+        let listener = TcpListener::bind(&bind_addr).unwrap();
+        Server { listener: listener,
+                 connections: Slab::new_starting_at(Token(2), 16),
+                 db: HashMap::new(),
+                 binary: false,
+                 idle_timeout: 1000,
+                 wheel: iter::repeat(Vec::new()).take(WHEEL_BUCKETS).collect(),
+                 wheel_pos: 0,
+                 udp_socket: None,
+                 udp_outbox: VecDeque::new(),
+                 tls_config: None }
+    }
+
+    /// Regression test: removing a connection outside of the idle-sweep must also drop it out of
+    /// whichever wheel bucket it lives in, or the stale token sits in `wheel` until the sweep
+    /// reaches that bucket, by which point the slab slot may belong to an unrelated connection.
+    #[test]
+    fn remove_connection_clears_its_wheel_bucket() {
+        let mut server = test_server();
+        let bucket = 2;
+        let connection = Connection::new(accept_loopback(), Framing::Text, bucket, 0, &None);
+        let token = server.connections.insert(connection).unwrap();
+        server.wheel[bucket].push(token);
+
+        server.remove_connection(token);
+
+        assert!(server.connections.get(token).is_none());
+        assert!(!server.wheel[bucket].contains(&token));
+    }
 }